@@ -5,19 +5,22 @@ extern crate cpython;
 extern crate rusoto_core;
 extern crate rusoto_ec2;
 
-use crowbar::{Value, Context, LambdaResult};
+use crowbar::{Value, LambdaContext, LambdaResult};
 use rusoto_core::{DefaultCredentialsProvider, Region, default_tls_client};
 use rusoto_ec2::{Ec2, Ec2Client, DescribeRegionsRequest};
 use std::default::Default;
 use std::env;
 use std::str::FromStr;
 
-fn list_regions(_: Value, _: Context) -> LambdaResult {
-    let provider = DefaultCredentialsProvider::new()?;
-    let region_str = env::var("AWS_DEFAULT_REGION")?;
-    let client = Ec2Client::new(default_tls_client()?,
-                                provider,
-                                Region::from_str(&region_str)?);
+fn init_client() -> Ec2Client {
+    let provider = DefaultCredentialsProvider::new().expect("failed to load AWS credentials");
+    let region_str = env::var("AWS_DEFAULT_REGION").expect("AWS_DEFAULT_REGION is not set");
+    Ec2Client::new(default_tls_client().expect("failed to create TLS client"),
+                   provider,
+                   Region::from_str(&region_str).expect("invalid AWS_DEFAULT_REGION"))
+}
+
+fn list_regions(client: &Ec2Client, _: Value, _: LambdaContext) -> LambdaResult {
     let input: DescribeRegionsRequest = Default::default();
 
     match client.describe_regions(&input)?.regions {
@@ -35,4 +38,4 @@ fn list_regions(_: Value, _: Context) -> LambdaResult {
     }
 }
 
-lambda!(list_regions);
+lambda!(state Ec2Client = init_client; list_regions);
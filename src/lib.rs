@@ -3,7 +3,9 @@
 //!
 //! # Usage
 //!
-//! Add both crowbar and cpython to your `Cargo.toml`:
+//! Add both crowbar and cpython to your `Cargo.toml`. AWS Lambda's Python 2.7 execution
+//! environment is the long-standing target, and is what `cpython`'s `python27-sys` feature
+//! builds against:
 //!
 //! ```toml
 //! [dependencies]
@@ -11,6 +13,20 @@
 //! cpython = { version = "*", default-features = false, features = ["python27-sys"] }
 //! ```
 //!
+//! AWS is retiring the Python 2.7 runtime, so new functions should target the Python 3.6+
+//! execution environment instead by swapping in the `python3-sys` feature:
+//!
+//! ```toml
+//! [dependencies]
+//! crowbar = "0.1"
+//! cpython = { version = "*", default-features = false, features = ["python3-sys"] }
+//! ```
+//!
+//! Both build the same way and `lambda!` emits the right entry symbol for either (`initliblambda`
+//! for Python 2, `PyInit_liblambda` for Python 3) without any changes to your code — the
+//! feature you pick only changes which `libpython` the resulting `liblambda.so` links against,
+//! so make sure it matches the execution environment you deploy to.
+//!
 //! Use macros from both crates:
 //!
 //! ```rust,ignore
@@ -50,8 +66,8 @@
 //! ```
 //!
 //! `cargo build` will now build a `liblambda.so`. Put this in a zip file and upload it to an AWS
-//! Lambda function. You will need to use the Python 2.7 execution environment with the handler
-//! configured as `liblambda.handler`.
+//! Lambda function. Use whichever execution environment matches the `cpython` feature you built
+//! against (Python 2.7 or Python 3.6+), with the handler configured as `liblambda.handler`.
 //!
 //! For best results, it's important to build the shared library on a system using the same
 //! libraries as the Lambda execution environment. Since Lambda uses Amazon Linux, the easiest way
@@ -65,6 +81,8 @@
 
 extern crate cpython;
 extern crate cpython_json;
+extern crate log;
+extern crate serde;
 extern crate serde_json;
 
 #[doc(hidden)]
@@ -77,13 +95,19 @@ pub use serde_json::value::Value;
 /// (https://doc.rust-lang.org/stable/book/error-handling.html#error-handling-with-boxerror) so
 /// that any `Error` can be thrown within your Lambda function.
 ///
-/// If an error is thrown, it is converted to a Python `RuntimeError`, and the `Debug` string for
-/// the `Error` returned is used as the value.
+/// If an error is thrown, it is converted to a Python `RuntimeError` with the `Debug` string for
+/// the `Error` used as the value, unless it downcasts to
+/// [`LambdaValueError`](struct.LambdaValueError.html) or
+/// [`LambdaTimeoutError`](struct.LambdaTimeoutError.html), in which case its exception type and
+/// `Display` message are used instead.
 pub type LambdaResult = Result<Value, Box<std::error::Error>>;
 
 use cpython::{Python, PyUnicode, PyTuple, PyErr, PythonObject, PythonObjectWithTypeObject,
               ObjectProtocol};
 use cpython_json::{from_json, to_json};
+use std::cell::UnsafeCell;
+use std::sync::{Mutex, Once};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Provides a view into the `context` object available to Lambda functions.
 ///
@@ -94,16 +118,54 @@ pub struct LambdaContext<'a> {
     py: &'a Python<'a>,
     py_context: &'a PyObject,
     string_storage: [String; 7],
+    client_context: Option<Value>,
+    cognito_identity: Option<Value>,
 }
 
 impl<'a> LambdaContext<'a> {
     fn new(py: &'a Python, py_context: &'a PyObject) -> PyResult<LambdaContext<'a>> {
+        // `extract::<String>` goes through cpython's `FromPyObject` impl for `String`, which
+        // already accepts both Python 2's `str`/`unicode` and Python 3's `str`, so this works
+        // unchanged whichever `cpython` Python-version feature crowbar is built against.
         macro_rules! str_attr {
             ($x:expr) => {
                 py_context.getattr(*py, $x)?.extract::<String>(*py)?;
             }
         }
 
+        // `client_context` and `cognito_identity` are instances of the runtime's own
+        // `ClientContext`/`CognitoIdentity` classes, not plain dicts, so `to_json` fails on the
+        // object itself. Their documented sub-attributes (`.client`/`.custom`/`.env`, and
+        // `.cognito_identity_id`/`.cognito_identity_pool_id`) are plain strings/dicts though, so
+        // build the nested JSON value out of those instead of converting the whole object. A
+        // sub-attribute that's missing or still fails to convert is omitted rather than failing
+        // the whole invocation.
+        macro_rules! sub_attr_json {
+            ($obj:expr, $x:expr) => {
+                match $obj.getattr(*py, $x) {
+                    Ok(ref sub) if !sub.is_none(*py) => to_json(*py, sub).ok(),
+                    _ => None,
+                }
+            }
+        }
+
+        macro_rules! nested_json_attr {
+            ($x:expr, [$($field:expr),*]) => {
+                match py_context.getattr(*py, $x) {
+                    Ok(ref obj) if !obj.is_none(*py) => {
+                        let mut map = serde_json::Map::new();
+                        $(
+                        if let Some(value) = sub_attr_json!(obj, $field) {
+                            map.insert($field.to_string(), value);
+                        }
+                        )*
+                        Some(Value::Object(map))
+                    }
+                    _ => None,
+                }
+            }
+        }
+
         let string_storage: [String; 7] = [str_attr!("function_name"),
                                            str_attr!("function_version"),
                                            str_attr!("invoked_function_arn"),
@@ -112,10 +174,26 @@ impl<'a> LambdaContext<'a> {
                                            str_attr!("log_group_name"),
                                            str_attr!("log_stream_name")];
 
+        let client_context = nested_json_attr!("client_context", ["client", "custom", "env"]);
+        let cognito_identity = nested_json_attr!("cognito_identity",
+                                                  ["cognito_identity_id", "cognito_identity_pool_id"]);
+
+        // Refresh the log context as soon as the invocation's context object is available, so
+        // any `log`-crate records emitted during this invocation (even before the first
+        // explicit `context.log(...)` call) are tagged with its `aws_request_id` rather than a
+        // stale value left over from a previous invocation.
+        set_log_context(LogContext {
+            aws_request_id: Some(string_storage[4].clone()),
+            function_name: Some(string_storage[0].clone()),
+            log_stream_name: Some(string_storage[6].clone()),
+        });
+
         Ok(LambdaContext {
             py: py,
             py_context: py_context,
             string_storage: string_storage,
+            client_context: client_context,
+            cognito_identity: cognito_identity,
         })
     }
 
@@ -169,6 +247,23 @@ impl<'a> LambdaContext<'a> {
         &self.string_storage[6]
     }
 
+    /// The mobile client context, if the function was invoked through the AWS Mobile SDK. This
+    /// includes the `client`, `custom`, and `env` sub-objects, converted to a JSON `Value`.
+    ///
+    /// Returns `None` if the function was not invoked through the AWS Mobile SDK.
+    pub fn client_context(&self) -> Option<Value> {
+        self.client_context.clone()
+    }
+
+    /// Information about the Amazon Cognito identity that authorized the request, if the function
+    /// was invoked through the AWS Mobile SDK. This includes the `cognito_identity_id` and
+    /// `cognito_identity_pool_id` fields, converted to a JSON `Value`.
+    ///
+    /// Returns `None` if the function was not invoked through the AWS Mobile SDK.
+    pub fn cognito_identity(&self) -> Option<Value> {
+        self.cognito_identity.clone()
+    }
+
     /// Returns the remaining execution time, in milliseconds, until AWS Lambda terminates the
     /// function.
     ///
@@ -184,6 +279,157 @@ impl<'a> LambdaContext<'a> {
             .and_then(|x| x.extract::<u64>(*self.py))
             .map_err(|_| ContextError::GetRemainingTimeFailed)
     }
+
+    /// Emits a single-line, newline-terminated JSON record to stdout, enriched with the
+    /// invocation's `aws_request_id`, `function_name`, and `log_stream_name` plus a timestamp,
+    /// so the event is directly filterable by CloudWatch Logs Insights.
+    ///
+    /// `LambdaContext::new` already refreshes the context `CrowbarLogger` attaches to records,
+    /// so this doesn't need to (and, being `&self`, can't predate the `LambdaContext` it's
+    /// called on).
+    pub fn log(&self, level: LogLevel, message: &Value) {
+        log_record(level, message);
+    }
+}
+
+/// Severity of a structured log record emitted via [`LambdaContext::log`](struct.LambdaContext.html#method.log).
+///
+/// These mirror the levels used by the [`log`](https://docs.rs/log) crate so that
+/// [`CrowbarLogger`](struct.CrowbarLogger.html) can forward `log!`/`info!`/`error!` records
+/// through the same format without a translation step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> LogLevel {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+/// The subset of `LambdaContext` that's attached to log records. Stored separately (as owned,
+/// optional `String`s) so it can be shared with `CrowbarLogger`, which runs outside of any
+/// particular invocation's borrowed `LambdaContext`.
+///
+/// `LambdaContext::new` refreshes this at the start of every invocation, so records logged via
+/// the `log` crate anywhere during the handler's execution are tagged correctly. A field stays
+/// `None` (and is omitted from the record, rather than written as an empty string) until the
+/// first `LambdaContext` is constructed; there is a narrow window between invocations, after one
+/// `LambdaContext` is dropped and before the next is constructed, where a field can still hold
+/// the previous invocation's value; avoid relying on `log`-crate output logged from outside a
+/// handler body if that matters for your use case.
+#[derive(Clone)]
+struct LogContext {
+    aws_request_id: Option<String>,
+    function_name: Option<String>,
+    log_stream_name: Option<String>,
+}
+
+impl LogContext {
+    const fn new() -> LogContext {
+        LogContext {
+            aws_request_id: None,
+            function_name: None,
+            log_stream_name: None,
+        }
+    }
+}
+
+static LOG_CONTEXT: Mutex<LogContext> = Mutex::new(LogContext::new());
+
+fn set_log_context(context: LogContext) {
+    *LOG_CONTEXT.lock().unwrap() = context;
+}
+
+/// Serializes and prints a single structured log record, using whichever `LogContext` was most
+/// recently attached by `LambdaContext::new`.
+fn log_record(level: LogLevel, message: &Value) {
+    let context = LOG_CONTEXT.lock().unwrap().clone();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000)
+        .unwrap_or(0);
+
+    println!("{}", build_record(level, &context, message, timestamp));
+}
+
+/// Builds the JSON value for a single structured log record. Pulled out of `log_record` as a
+/// pure function so the record shape can be unit tested without a Python interpreter.
+fn build_record(level: LogLevel, context: &LogContext, message: &Value, timestamp: u64) -> Value {
+    let mut record = serde_json::Map::new();
+    record.insert("timestamp".to_string(), Value::from(timestamp));
+    record.insert("level".to_string(), Value::from(level.as_str()));
+    if let Some(ref aws_request_id) = context.aws_request_id {
+        record.insert("aws_request_id".to_string(), Value::from(aws_request_id.clone()));
+    }
+    if let Some(ref function_name) = context.function_name {
+        record.insert("function_name".to_string(), Value::from(function_name.clone()));
+    }
+    if let Some(ref log_stream_name) = context.log_stream_name {
+        record.insert("log_stream_name".to_string(), Value::from(log_stream_name.clone()));
+    }
+    record.insert("message".to_string(), message.clone());
+
+    Value::Object(record)
+}
+
+/// A [`log::Log`](https://docs.rs/log/*/log/trait.Log.html) implementation that emits records in
+/// the same structured JSON format as [`LambdaContext::log`](struct.LambdaContext.html#method.log),
+/// so existing `log!`/`info!`/`error!`/etc. call sites are filterable in CloudWatch Logs Insights
+/// without being rewritten to call `context.log` directly.
+///
+/// Install it once per container, e.g. from your `lambda!` `state` initializer:
+///
+/// ```rust,ignore
+/// crowbar::CrowbarLogger::init().expect("failed to install logger");
+/// ```
+///
+/// `LambdaContext::new` refreshes the `aws_request_id`, `function_name`, and `log_stream_name`
+/// fields `CrowbarLogger` attaches to records as soon as each invocation's `LambdaContext` is
+/// built, so `log`-crate records emitted anywhere in the handler body are tagged correctly.
+pub struct CrowbarLogger;
+
+impl CrowbarLogger {
+    /// Installs `CrowbarLogger` as the global `log` backend.
+    pub fn init() -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(CrowbarLogger))
+            .map(|()| log::set_max_level(log::LevelFilter::Trace))
+    }
+}
+
+impl log::Log for CrowbarLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        log_record(LogLevel::from(record.level()), &Value::String(format!("{}", record.args())));
+    }
+
+    fn flush(&self) {}
 }
 
 /// Error enum for things that can go wrong while processing the context object.
@@ -223,13 +469,159 @@ pub fn handler<F>(py: Python, f: F, py_event: PyObject, py_context: PyObject) ->
     let event = to_json(py, &py_event).or_else(|e| Err(e.to_pyerr(py)))?;
     let result = match f(event, LambdaContext::new(&py, &py_context)?) {
         Ok(r) => r,
-        Err(e) => {
-            return Err(PyErr {
-                ptype: cpython::exc::RuntimeError::type_object(py).into_object(),
-                pvalue: Some(PyUnicode::new(py, &format!("{:?}", e)).into_object()),
-                ptraceback: None,
-            })
+        Err(e) => return Err(lambda_error_to_pyerr(py, &*e)),
+    };
+    from_json(py, result).or_else(|e| Err(e.to_pyerr(py)))
+}
+
+/// An error that is raised as a Python `ValueError`, signaling to the Lambda runtime that the
+/// invocation should not be retried (e.g. bad input or a failed validation).
+///
+/// `handler`/`handler_typed`/`handler_with_state` recognize this type and
+/// [`LambdaTimeoutError`](struct.LambdaTimeoutError.html) out of the box, via
+/// `lambda_error_to_pyerr`; there is currently no generic extension point for mapping other
+/// error types to other exception classes. Any other error is raised as a `RuntimeError` with
+/// its `Debug` representation.
+///
+/// This is a deliberate, smaller surface than a `LambdaError` trait that users could implement
+/// on their own error types: downcasting a boxed `std::error::Error` to an arbitrary trait a
+/// caller implements isn't possible on stable Rust without the caller also telling `handler`
+/// about their concrete type, so there's no way to make dispatch genuinely trait-driven here.
+/// Wrap your error in one of these two structs (or match on your error and construct one) to
+/// pick its Python exception type.
+#[derive(Debug)]
+pub struct LambdaValueError(pub String);
+
+impl std::fmt::Display for LambdaValueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LambdaValueError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+/// An error that is raised as a Python `IOError`, signaling to the Lambda runtime that the
+/// invocation failed transiently and should be retried.
+///
+/// `IOError` (rather than the Python 3-only `TimeoutError`) is used so this works unchanged
+/// whichever `cpython` Python-version feature crowbar is built against: in Python 2 it's a
+/// plain built-in exception commonly used for transient I/O failures (e.g. `socket.timeout` is
+/// a subclass), and in Python 3 it's an alias for `OSError`.
+#[derive(Debug)]
+pub struct LambdaTimeoutError(pub String);
+
+impl std::fmt::Display for LambdaTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LambdaTimeoutError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Builds the `PyErr` to raise for an error returned from a Lambda handler, picking the
+/// exception type for the two error structs crowbar provides, and falling back to a
+/// `RuntimeError` with the `Debug` representation of the error for anything else.
+fn lambda_error_to_pyerr(py: Python, err: &(std::error::Error + 'static)) -> PyErr {
+    if let Some(e) = err.downcast_ref::<LambdaValueError>() {
+        return PyErr {
+            ptype: cpython::exc::ValueError::type_object(py).into_object(),
+            pvalue: Some(PyUnicode::new(py, &format!("{}", e)).into_object()),
+            ptraceback: None,
+        };
+    }
+
+    if let Some(e) = err.downcast_ref::<LambdaTimeoutError>() {
+        return PyErr {
+            ptype: cpython::exc::IOError::type_object(py).into_object(),
+            pvalue: Some(PyUnicode::new(py, &format!("{}", e)).into_object()),
+            ptraceback: None,
+        };
+    }
+
+    PyErr {
+        ptype: cpython::exc::RuntimeError::type_object(py).into_object(),
+        pvalue: Some(PyUnicode::new(py, &format!("{:?}", err)).into_object()),
+        ptraceback: None,
+    }
+}
+
+/// Holds state that is built once and shared across warm invocations of a Lambda function.
+///
+/// This backs the `state` form of the `lambda!` macro, letting expensive resources (HTTP
+/// clients, connection pools, credential providers) be constructed a single time per container
+/// lifetime instead of on every invocation.
+#[doc(hidden)]
+pub struct OnceState<S> {
+    once: Once,
+    value: UnsafeCell<Option<S>>,
+}
+
+unsafe impl<S: Send> Sync for OnceState<S> {}
+
+impl<S> OnceState<S> {
+    #[doc(hidden)]
+    pub const fn new() -> OnceState<S> {
+        OnceState {
+            once: Once::new(),
+            value: UnsafeCell::new(None),
         }
+    }
+
+    #[doc(hidden)]
+    pub fn get_or_init<F: FnOnce() -> S>(&'static self, init: F) -> &'static S {
+        self.once.call_once(|| unsafe {
+            *self.value.get() = Some(init());
+        });
+        unsafe { (*self.value.get()).as_ref().unwrap() }
+    }
+}
+
+/// Converts an error that implements `Display` into a Python `ValueError`.
+fn to_pyerr<E: std::fmt::Display>(py: Python, err: &E) -> PyErr {
+    PyErr {
+        ptype: cpython::exc::ValueError::type_object(py).into_object(),
+        pvalue: Some(PyUnicode::new(py, &format!("{}", err)).into_object()),
+        ptraceback: None,
+    }
+}
+
+#[doc(hidden)]
+pub fn handler_typed<E, R, F>(py: Python, f: F, py_event: PyObject, py_context: PyObject) -> PyResult<PyObject>
+    where F: Fn(E, LambdaContext) -> Result<R, Box<std::error::Error>>,
+          E: serde::de::DeserializeOwned,
+          R: serde::Serialize
+{
+    let json = to_json(py, &py_event).or_else(|e| Err(e.to_pyerr(py)))?;
+    let event = serde_json::from_value::<E>(json).or_else(|e| Err(to_pyerr(py, &e)))?;
+    let result = match f(event, LambdaContext::new(&py, &py_context)?) {
+        Ok(r) => r,
+        Err(e) => return Err(lambda_error_to_pyerr(py, &*e)),
+    };
+    let value = serde_json::to_value(result).or_else(|e| Err(to_pyerr(py, &e)))?;
+    from_json(py, value).or_else(|e| Err(e.to_pyerr(py)))
+}
+
+#[doc(hidden)]
+pub fn handler_with_state<S, F>(py: Python,
+                                 state: &'static S,
+                                 f: F,
+                                 py_event: PyObject,
+                                 py_context: PyObject)
+                                 -> PyResult<PyObject>
+    where F: Fn(&'static S, Value, LambdaContext) -> LambdaResult
+{
+    let event = to_json(py, &py_event).or_else(|e| Err(e.to_pyerr(py)))?;
+    let result = match f(state, event, LambdaContext::new(&py, &py_context)?) {
+        Ok(r) => r,
+        Err(e) => return Err(lambda_error_to_pyerr(py, &*e)),
     };
     from_json(py, result).or_else(|e| Err(e.to_pyerr(py)))
 }
@@ -286,6 +678,59 @@ pub fn handler<F>(py: Python, f: F, py_event: PyObject, py_context: PyObject) ->
 /// lambda!(my_handler);
 /// # }
 /// ```
+///
+/// If you'd rather work with a strongly typed event and response than `Value`, use the `typed`
+/// form, which deserializes the event into `E` and serializes your response from `R` using serde:
+///
+/// ```rust,ignore
+/// #[macro_use(lambda)] extern crate crowbar;
+/// #[macro_use] extern crate cpython;
+/// #[macro_use] extern crate serde_derive;
+///
+/// use crowbar::LambdaContext;
+///
+/// #[derive(Deserialize)]
+/// struct MyEvent {
+///     name: String,
+/// }
+///
+/// #[derive(Serialize)]
+/// struct MyResponse {
+///     message: String,
+/// }
+///
+/// fn my_handler(event: MyEvent, _: LambdaContext) -> Result<MyResponse, Box<std::error::Error>> {
+///     Ok(MyResponse { message: format!("hello, {}!", event.name) })
+/// }
+///
+/// lambda!(typed my_handler);
+/// ```
+///
+/// If your function needs to share expensive resources (an HTTP client, a connection pool, a
+/// credential provider) across warm invocations, use the `state` form. The initializer runs
+/// exactly once per container lifetime, and every invocation after that receives a `&'static`
+/// reference to the value it produced:
+///
+/// ```rust,ignore
+/// #[macro_use(lambda)] extern crate crowbar;
+/// #[macro_use] extern crate cpython;
+///
+/// use crowbar::{Value, LambdaContext, LambdaResult};
+///
+/// struct Client {
+///     // ...
+/// }
+///
+/// fn init_client() -> Client {
+///     Client { /* ... */ }
+/// }
+///
+/// fn my_handler(client: &Client, event: Value, _: LambdaContext) -> LambdaResult {
+///     Ok(event)
+/// }
+///
+/// lambda!(state Client = init_client; my_handler);
+/// ```
 macro_rules! lambda {
     (@module ($module:ident, $py2:ident, $py3:ident) @handlers ($($handler:expr => $target:expr),*)) => {
         py_module_initializer!($module, $py2, $py3, |py, m| {
@@ -298,6 +743,73 @@ macro_rules! lambda {
         });
     };
 
+    (@module ($module:ident, $py2:ident, $py3:ident) @typed_handlers ($($handler:expr => $target:expr),*)) => {
+        py_module_initializer!($module, $py2, $py3, |py, m| {
+            $(
+            m.add(py, $handler, py_fn!(py, x(event: $crate::PyObject, context: $crate::PyObject) -> $crate::PyResult<$crate::PyObject> {
+                $crate::handler_typed(py, $target, event, context)
+            }))?;
+            )*
+            Ok(())
+        });
+    };
+
+    (crate $module:tt typed { $($handler:expr => $target:expr),* }) => {
+        lambda! { @module $module @typed_handlers ($($handler => $target),*) }
+    };
+
+    (crate $module:tt typed { $($handler:expr => $target:expr,)* }) => {
+        lambda! { @module $module @typed_handlers ($($handler => $target),*) }
+    };
+
+    (typed $($handler:expr => $target:expr),*) => {
+        lambda! { @module (liblambda, initliblambda, PyInit_liblambda) @typed_handlers ($($handler => $target),*) }
+    };
+
+    (typed $($handler:expr => $target:expr,)*) => {
+        lambda! { typed $($handler => $target),* }
+    };
+
+    (typed $f:expr) => {
+        lambda! { typed "handler" => $f, }
+    };
+
+    (@module ($module:ident, $py2:ident, $py3:ident) @state_handlers $state:ty = $init:expr; ($($handler:expr => $target:expr),*)) => {
+        py_module_initializer!($module, $py2, $py3, |py, m| {
+            // `py_fn!` expands its body into a standalone `fn` item, which can't capture an
+            // enclosing local — so `__CROWBAR_STATE` has to be a static (items, unlike locals,
+            // stay in scope inside nested fn items) and each handler body calls `get_or_init`
+            // itself rather than closing over a `let`-bound reference.
+            static __CROWBAR_STATE: $crate::OnceState<$state> = $crate::OnceState::new();
+            $(
+            m.add(py, $handler, py_fn!(py, x(event: $crate::PyObject, context: $crate::PyObject) -> $crate::PyResult<$crate::PyObject> {
+                $crate::handler_with_state(py, __CROWBAR_STATE.get_or_init($init), $target, event, context)
+            }))?;
+            )*
+            Ok(())
+        });
+    };
+
+    (crate $module:tt state $state:ty = $init:expr; { $($handler:expr => $target:expr),* }) => {
+        lambda! { @module $module @state_handlers $state = $init; ($($handler => $target),*) }
+    };
+
+    (crate $module:tt state $state:ty = $init:expr; { $($handler:expr => $target:expr,)* }) => {
+        lambda! { @module $module @state_handlers $state = $init; ($($handler => $target),*) }
+    };
+
+    (state $state:ty = $init:expr; $($handler:expr => $target:expr),*) => {
+        lambda! { @module (liblambda, initliblambda, PyInit_liblambda) @state_handlers $state = $init; ($($handler => $target),*) }
+    };
+
+    (state $state:ty = $init:expr; $($handler:expr => $target:expr,)*) => {
+        lambda! { state $state = $init; $($handler => $target),* }
+    };
+
+    (state $state:ty = $init:expr; $f:expr) => {
+        lambda! { state $state = $init; "handler" => $f, }
+    };
+
     (crate $module:tt { $($handler:expr => $target:expr),* }) => {
         lambda! { @module $module @handlers ($($handler => $target),*) }
     };
@@ -318,3 +830,54 @@ macro_rules! lambda {
         lambda! { "handler" => $f, }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{build_record, LogContext, LogLevel, Value};
+
+    #[test]
+    fn log_level_as_str_matches_log_crate_level_names() {
+        assert_eq!(LogLevel::Error.as_str(), "ERROR");
+        assert_eq!(LogLevel::Warn.as_str(), "WARN");
+        assert_eq!(LogLevel::Info.as_str(), "INFO");
+        assert_eq!(LogLevel::Debug.as_str(), "DEBUG");
+        assert_eq!(LogLevel::Trace.as_str(), "TRACE");
+    }
+
+    #[test]
+    fn log_level_from_log_level_is_a_faithful_mapping() {
+        assert_eq!(LogLevel::from(::log::Level::Error), LogLevel::Error);
+        assert_eq!(LogLevel::from(::log::Level::Warn), LogLevel::Warn);
+        assert_eq!(LogLevel::from(::log::Level::Info), LogLevel::Info);
+        assert_eq!(LogLevel::from(::log::Level::Debug), LogLevel::Debug);
+        assert_eq!(LogLevel::from(::log::Level::Trace), LogLevel::Trace);
+    }
+
+    #[test]
+    fn build_record_includes_populated_context_fields() {
+        let context = LogContext {
+            aws_request_id: Some("request-id".to_string()),
+            function_name: Some("my-function".to_string()),
+            log_stream_name: Some("log-stream".to_string()),
+        };
+        let record = build_record(LogLevel::Info, &context, &Value::from("hello"), 1234);
+
+        assert_eq!(record["timestamp"], Value::from(1234));
+        assert_eq!(record["level"], Value::from("INFO"));
+        assert_eq!(record["aws_request_id"], Value::from("request-id"));
+        assert_eq!(record["function_name"], Value::from("my-function"));
+        assert_eq!(record["log_stream_name"], Value::from("log-stream"));
+        assert_eq!(record["message"], Value::from("hello"));
+    }
+
+    #[test]
+    fn build_record_omits_unset_context_fields() {
+        let context = LogContext::new();
+        let record = build_record(LogLevel::Error, &context, &Value::from("boom"), 5678);
+
+        assert_eq!(record.get("aws_request_id"), None);
+        assert_eq!(record.get("function_name"), None);
+        assert_eq!(record.get("log_stream_name"), None);
+        assert_eq!(record["message"], Value::from("boom"));
+    }
+}